@@ -2,6 +2,7 @@
 
 use {
     std::{
+        future::Future,
         io,
         mem::forget,
         num::ParseIntError,
@@ -11,7 +12,10 @@ use {
         },
         sync::Arc,
         thread,
-        time::Duration,
+        time::{
+            Duration,
+            Instant,
+        },
     },
     sysinfo::{
         Pid,
@@ -33,9 +37,14 @@ use {
 /// Since creating a directory if it does not exist is an atomic operation on most operating systems,
 /// this can be used as a quick-and-dirty cross-process mutex.
 ///
-/// To guard against processes exiting without properly removing the lock, a file containing the current process ID is created inside the lock.
-/// If no process with that ID exists, another process may claim the lock for itself.
-/// If the file does not exist, the constructor waits until it does (or until the directory is removed).
+/// To guard against processes exiting without properly removing the lock, a file containing the owning host's hostname,
+/// the current process ID, and (if available) that process's start time is created inside the lock.
+/// If that process is no longer running on that host, another process may claim the lock for itself; the start time is
+/// used to tell the original holder apart from an unrelated process the OS has since reused its PID for, and the
+/// hostname is used to avoid stealing a lock that's still held by a live process on another machine (e.g. on a
+/// networked filesystem), since liveness can only be checked on the local host.
+/// If the file does not exist yet, it is assumed to still be being written, and the lock directory's modification time is used
+/// to tell a lock that's genuinely mid-write from one abandoned by a process that crashed before finishing the write.
 ///
 /// Of course, this is still not completely fail-proof since the user or other processes could mess with the lock directory.
 ///
@@ -43,12 +52,22 @@ use {
 #[must_use = "should call the drop_async method to unlock"]
 pub struct DirLock(PathBuf);
 
+/// The default value of [`Builder::stale_grace_period`].
+const DEFAULT_STALE_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// The default value of [`Builder::max_stale_retries`].
+const DEFAULT_MAX_STALE_RETRIES: u32 = 5;
+
 /// An error that can occur when locking or unlocking a [`DirLock`].
 #[derive(Debug, Error, Clone)]
 #[allow(missing_docs)]
 pub enum Error {
+    /// Returned by [`DirLock::try_new`]/[`DirLock::try_new_sync`] when the lock is currently held by a live process.
+    #[error("directory lock is already held")] AlreadyHeld,
     #[error("I/O error{}: {}", if let Some(path) = .1 { format!(" at {}", path.display()) } else { String::default() }, .0)] Io(#[source] Arc<io::Error>, Option<PathBuf>),
     #[error(transparent)] ParseInt(#[from] ParseIntError),
+    /// Returned by [`Builder::build`]/[`Builder::build_sync`] when the configured timeout elapses before the lock could be acquired.
+    #[error("timed out waiting for directory lock")] Timeout,
 }
 
 trait IoResultExt {
@@ -74,36 +93,57 @@ impl<T, E: IoResultExt> IoResultExt for Result<T, E> {
 }
 
 impl DirLock {
-    /// Acquires a directory lock at the given path, without blocking the thread.
+    /// Starts building a lock acquisition with a configurable timeout, poll interval, and stale-lock reclaim budget.
     ///
-    /// See the type-level docs for details.
-    pub async fn new(path: impl AsRef<Path>) -> Result<Self, Error> {
-        let path = path.as_ref().to_owned();
+    /// See [`Builder`] for the available options and their defaults.
+    pub fn builder(path: impl AsRef<Path>) -> Builder {
+        Builder {
+            path: path.as_ref().to_owned(),
+            timeout: None,
+            poll_interval: Duration::from_secs(1),
+            max_stale_retries: DEFAULT_MAX_STALE_RETRIES,
+            stale_grace_period: DEFAULT_STALE_GRACE_PERIOD,
+        }
+    }
+
+    /// Tries to acquire a directory lock at the given path without waiting.
+    ///
+    /// Performs a `create_dir` attempt. If the directory already exists, the stale-lock reclaim
+    /// check is run; if the lock turns out to be stale, it is reclaimed and `create_dir` is
+    /// retried, up to 5 times, so a pathological flapping pidfile can't turn this "single
+    /// attempt" call into a busy loop. If the lock is still held by a live process (or the
+    /// retry budget is exhausted), this returns [`Error::AlreadyHeld`] instead of waiting for it to free up.
+    pub async fn try_new(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let mut stale_retries_remaining = DEFAULT_MAX_STALE_RETRIES;
+        Self::try_new_limited(&path.as_ref().to_owned(), &mut stale_retries_remaining, DEFAULT_STALE_GRACE_PERIOD).await
+    }
+
+    async fn try_new_limited(path: &Path, stale_retries_remaining: &mut u32, stale_grace_period: Duration) -> Result<Self, Error> {
         loop {
-            match fs::create_dir(&path).await {
+            match fs::create_dir(path).await {
                 Ok(()) => {
                     let pidfile = path.join("pid");
-                    fs::write(&pidfile, format!("{}\n", std::process::id())).await.at(pidfile)?;
-                    return Ok(Self(path))
+                    fs::write(&pidfile, pidfile_contents()).await.at(pidfile)?;
+                    return Ok(Self(path.to_owned()))
                 }
                 Err(e) => match e.kind() {
                     io::ErrorKind::AlreadyExists => {
                         let pidfile = path.join("pid");
-                        if match fs::read_to_string(&pidfile).await {
-                            Ok(buf) => {
-                                !buf.is_empty() // assume pidfile is still being written if empty //TODO check timestamp
-                                && !pid_exists(buf.trim().parse()?)
-                            }
+                        let stale = match fs::read_to_string(&pidfile).await {
+                            Ok(buf) if buf.is_empty() => is_abandoned(path, stale_grace_period).await?, // pidfile may still be being written; only reclaim once it's old enough
+                            Ok(buf) => is_stale(&buf)?,
                             Err(e) => if e.kind() == io::ErrorKind::NotFound {
-                                false
+                                is_abandoned(path, stale_grace_period).await? // lock dir may have just been created; only reclaim once it's old enough
                             } else {
-                                return Err(e.at(path.join("pid")))
+                                return Err(e.at(pidfile))
                             },
-                        } {
-                            clean_up_path(&path).await?;
+                        };
+                        if stale && *stale_retries_remaining > 0 {
+                            *stale_retries_remaining -= 1;
+                            clean_up_path(path).await?;
+                            continue // the lock was just freed, retry create_dir instead of reporting AlreadyHeld
                         }
-                        sleep(Duration::from_secs(1)).await;
-                        continue
+                        return Err(Error::AlreadyHeld)
                     }
                     _ => return Err(e.at(path)),
                 },
@@ -111,34 +151,51 @@ impl DirLock {
         }
     }
 
-    /// Blocks the current thread until the lock can be established.
-    pub fn new_sync(path: &impl AsRef<Path>) -> Result<Self, Error> {
-        let path = path.as_ref().to_owned();
+    /// Acquires a directory lock at the given path, without blocking the thread.
+    ///
+    /// See the type-level docs for details.
+    pub async fn new(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::builder(path).build().await
+    }
+
+    /// Tries to acquire a directory lock at the given path without waiting, blocking the current thread for the I/O.
+    ///
+    /// Performs a `create_dir` attempt. If the directory already exists, the stale-lock reclaim
+    /// check is run; if the lock turns out to be stale, it is reclaimed and `create_dir` is
+    /// retried, up to 5 times, so a pathological flapping pidfile can't turn this "single
+    /// attempt" call into a busy loop. If the lock is still held by a live process (or the
+    /// retry budget is exhausted), this returns [`Error::AlreadyHeld`] instead of blocking until it frees up.
+    pub fn try_new_sync(path: &impl AsRef<Path>) -> Result<Self, Error> {
+        let mut stale_retries_remaining = DEFAULT_MAX_STALE_RETRIES;
+        Self::try_new_limited_sync(&path.as_ref().to_owned(), &mut stale_retries_remaining, DEFAULT_STALE_GRACE_PERIOD)
+    }
+
+    fn try_new_limited_sync(path: &Path, stale_retries_remaining: &mut u32, stale_grace_period: Duration) -> Result<Self, Error> {
         loop {
-            match std::fs::create_dir(&path) {
+            match std::fs::create_dir(path) {
                 Ok(()) => {
                     let pidfile = path.join("pid");
-                    std::fs::write(&pidfile, format!("{}\n", std::process::id())).at(pidfile)?;
-                    return Ok(Self(path))
+                    std::fs::write(&pidfile, pidfile_contents()).at(pidfile)?;
+                    return Ok(Self(path.to_owned()))
                 }
                 Err(e) => match e.kind() {
                     io::ErrorKind::AlreadyExists => {
                         let pidfile = path.join("pid");
-                        if match std::fs::read_to_string(&pidfile) {
-                            Ok(buf) => {
-                                !buf.is_empty() // assume pidfile is still being written if empty //TODO check timestamp
-                                && !pid_exists(buf.trim().parse()?)
-                            }
+                        let stale = match std::fs::read_to_string(&pidfile) {
+                            Ok(buf) if buf.is_empty() => is_abandoned_sync(path, stale_grace_period)?, // pidfile may still be being written; only reclaim once it's old enough
+                            Ok(buf) => is_stale(&buf)?,
                             Err(e) => if e.kind() == io::ErrorKind::NotFound {
-                                false
+                                is_abandoned_sync(path, stale_grace_period)? // lock dir may have just been created; only reclaim once it's old enough
                             } else {
-                                return Err(e.at(path.join("pid")))
+                                return Err(e.at(pidfile))
                             },
-                        } {
-                            clean_up_path_sync(&path)?;
+                        };
+                        if stale && *stale_retries_remaining > 0 {
+                            *stale_retries_remaining -= 1;
+                            clean_up_path_sync(path)?;
+                            continue // the lock was just freed, retry create_dir instead of reporting AlreadyHeld
                         }
-                        thread::sleep(Duration::from_secs(1));
-                        continue
+                        return Err(Error::AlreadyHeld)
                     }
                     _ => return Err(e.at(path)),
                 },
@@ -146,6 +203,38 @@ impl DirLock {
         }
     }
 
+    /// Blocks the current thread until the lock can be established.
+    pub fn new_sync(path: &impl AsRef<Path>) -> Result<Self, Error> {
+        Self::builder(path.as_ref()).build_sync()
+    }
+
+    /// Acquires a directory lock at the given path, runs `f`, then releases the lock, forwarding `f`'s return value.
+    ///
+    /// Unlike manually pairing [`new`](Self::new) with [`drop_async`](Self::drop_async), this releases the lock
+    /// through the async cleanup path even if `f` returns an error, so an I/O error from releasing the lock is
+    /// returned to the caller instead of panicking in [`Drop`]. If `f` panics, the guard is still dropped and
+    /// cleans up the lock directory (blocking, as in the regular [`Drop`] impl).
+    pub async fn with_lock_async<T, F: FnOnce() -> Fut, Fut: Future<Output = T>>(path: impl AsRef<Path>, f: F) -> Result<T, Error> {
+        let lock = Self::new(path).await?;
+        let result = f().await;
+        let cleanup = lock.clean_up().await;
+        forget(lock); // disarm the guard before propagating a cleanup error, so Drop doesn't try (and potentially panic) again
+        cleanup?;
+        Ok(result)
+    }
+
+    /// Acquires a directory lock at the given path, runs `f`, then releases the lock, forwarding `f`'s return value.
+    ///
+    /// This is the blocking equivalent of [`with_lock_async`](Self::with_lock_async).
+    pub fn with_lock<T>(path: &impl AsRef<Path>, f: impl FnOnce() -> T) -> Result<T, Error> {
+        let lock = Self::new_sync(path)?;
+        let result = f();
+        let cleanup = lock.clean_up_sync();
+        forget(lock); // disarm the guard before propagating a cleanup error, so Drop doesn't try (and potentially panic) again
+        cleanup?;
+        Ok(result)
+    }
+
     /// Return the contained Path.
     pub fn path(&self) -> &Path {
         self.0.as_path()
@@ -167,6 +256,85 @@ impl DirLock {
     }
 }
 
+/// Configures how [`DirLock::new`]/[`DirLock::new_sync`] wait for a lock to become available.
+///
+/// Created with [`DirLock::builder`].
+pub struct Builder {
+    path: PathBuf,
+    timeout: Option<Duration>,
+    poll_interval: Duration,
+    max_stale_retries: u32,
+    stale_grace_period: Duration,
+}
+
+impl Builder {
+    /// Sets the maximum time to wait for the lock before giving up with [`Error::Timeout`].
+    ///
+    /// By default, there is no timeout.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the time to wait between polls of a held lock. Defaults to 1 second.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Sets the maximum number of times a seemingly-stale lock may be reclaimed during a single acquisition,
+    /// so a pathological flapping pidfile can't be reclaimed forever. Defaults to 5.
+    pub fn max_stale_retries(mut self, max_stale_retries: u32) -> Self {
+        self.max_stale_retries = max_stale_retries;
+        self
+    }
+
+    /// Sets how long a lock directory with an empty or missing pidfile is assumed to still be mid-write
+    /// before it is considered abandoned and reclaimed. Defaults to 30 seconds.
+    pub fn stale_grace_period(mut self, stale_grace_period: Duration) -> Self {
+        self.stale_grace_period = stale_grace_period;
+        self
+    }
+
+    /// Acquires the lock, without blocking the thread.
+    pub async fn build(self) -> Result<DirLock, Error> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let mut stale_retries_remaining = self.max_stale_retries;
+        loop {
+            match DirLock::try_new_limited(&self.path, &mut stale_retries_remaining, self.stale_grace_period).await {
+                Ok(lock) => return Ok(lock),
+                Err(Error::AlreadyHeld) => {
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return Err(Error::Timeout)
+                    }
+                    sleep(deadline.map_or(self.poll_interval, |deadline| self.poll_interval.min(deadline.saturating_duration_since(Instant::now())))).await;
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Acquires the lock, blocking the current thread while doing so.
+    pub fn build_sync(self) -> Result<DirLock, Error> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let mut stale_retries_remaining = self.max_stale_retries;
+        loop {
+            match DirLock::try_new_limited_sync(&self.path, &mut stale_retries_remaining, self.stale_grace_period) {
+                Ok(lock) => return Ok(lock),
+                Err(Error::AlreadyHeld) => {
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return Err(Error::Timeout)
+                    }
+                    thread::sleep(deadline.map_or(self.poll_interval, |deadline| self.poll_interval.min(deadline.saturating_duration_since(Instant::now()))));
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
 impl Drop for DirLock {
     /// Unlocks this lock, blocking the current thread while doing so.
     ///
@@ -207,7 +375,71 @@ fn clean_up_path_sync(path: &Path) -> Result<(), Error> {
     Ok(())
 }
 
-fn pid_exists(pid: Pid) -> bool {
+/// Returns `true` if `pid` names a currently running process.
+///
+/// If `expected_start_time` is given (seconds since epoch, as reported by [`sysinfo::Process::start_time`]),
+/// the process must also have been started at that time, guarding against the OS having recycled the PID
+/// for an unrelated process.
+fn pid_live(pid: Pid, expected_start_time: Option<u64>) -> bool {
+    let mut system = sysinfo::System::default();
+    system.refresh_processes_specifics(ProcessesToUpdate::Some(&[pid]), true, ProcessRefreshKind::default());
+    system.process(pid).is_some_and(|process| expected_start_time.is_none_or(|expected| process.start_time() == expected))
+}
+
+/// The contents to write to a freshly created lock's pidfile: the local hostname, the current process ID, and
+/// (if available) that process's start time, one per line.
+fn pidfile_contents() -> String {
+    let pid = std::process::id();
+    let hostname = sysinfo::System::host_name().unwrap_or_default();
+    match process_start_time(Pid::from_u32(pid)) {
+        Some(start_time) => format!("{hostname}\n{pid}\n{start_time}\n"),
+        None => format!("{hostname}\n{pid}\n"),
+    }
+}
+
+fn process_start_time(pid: Pid) -> Option<u64> {
     let mut system = sysinfo::System::default();
-    system.refresh_processes_specifics(ProcessesToUpdate::Some(&[pid]), true, ProcessRefreshKind::default()) > 0
+    system.refresh_processes_specifics(ProcessesToUpdate::Some(&[pid]), true, ProcessRefreshKind::default());
+    system.process(pid).map(|process| process.start_time())
+}
+
+/// Parses a pidfile, returning the hostname it was written on (if any, for backward compatibility with the old
+/// single-line `pid`-only format), the PID it names, and the owning process's start time (if recorded).
+fn parse_pidfile(buf: &str) -> Result<(Option<&str>, Pid, Option<u64>), ParseIntError> {
+    let mut lines = buf.lines();
+    let first = lines.next().unwrap_or_default();
+    Ok(match lines.next() {
+        Some(pid) => match lines.next() {
+            Some(start_time) => (Some(first), pid.trim().parse()?, Some(start_time.trim().parse()?)),
+            None => (Some(first), pid.trim().parse()?, None),
+        },
+        None => (None, first.trim().parse()?, None),
+    })
+}
+
+/// Determines whether the process that owns a pidfile is no longer running, i.e. whether the lock is stale and may be reclaimed.
+///
+/// A lock whose pidfile names a different host than the local one is never considered stale, since liveness can only be
+/// checked on the local host. If the local hostname can't be determined, this can't be told apart from a local lock, so
+/// it falls back to the local liveness check rather than treating every lock as remote and refusing to ever reclaim it.
+/// If the pidfile records the owning process's start time, a live PID whose start time doesn't match is also considered stale,
+/// since the OS may have reused the PID after the original holder exited.
+fn is_stale(buf: &str) -> Result<bool, ParseIntError> {
+    let (hostname, pid, start_time) = parse_pidfile(buf)?;
+    Ok(match (hostname, sysinfo::System::host_name()) {
+        (Some(hostname), Some(local_hostname)) if hostname != local_hostname => false,
+        _ => !pid_live(pid, start_time),
+    })
+}
+
+/// Determines whether a lock directory with an empty or missing pidfile is stale, i.e. whether its creator
+/// crashed between `create_dir` and writing the pidfile rather than still being in the middle of doing so.
+async fn is_abandoned(path: &Path, grace_period: Duration) -> Result<bool, Error> {
+    let mtime = fs::metadata(path).await.at(path)?.modified().at(path)?;
+    Ok(mtime.elapsed().is_ok_and(|elapsed| elapsed >= grace_period))
+}
+
+fn is_abandoned_sync(path: &Path, grace_period: Duration) -> Result<bool, Error> {
+    let mtime = std::fs::metadata(path).at(path)?.modified().at(path)?;
+    Ok(mtime.elapsed().is_ok_and(|elapsed| elapsed >= grace_period))
 }